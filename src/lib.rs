@@ -0,0 +1,8 @@
+//! TIS-100 node emulator: assembly parser/interpreter, the port handshake
+//! and its Unix-socket and in-process transports, the multi-node runtime,
+//! and the puzzle harness built on top of it.
+
+pub mod node;
+pub mod program;
+pub mod puzzle;
+pub mod runtime;
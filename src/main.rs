@@ -1,9 +1,9 @@
-mod node;
-
-use node::{Node, PeerPids, Register};
 use std::path::PathBuf;
 use structopt::clap::AppSettings;
 use structopt::StructOpt;
+use tis100node::node::{Node, PeerAddrs};
+use tis100node::program::{Interpreter, Program};
+use tis100node::puzzle;
 
 #[derive(StructOpt)]
 #[structopt(setting = AppSettings::ColorAuto)]
@@ -14,53 +14,52 @@ struct Args {
 	#[structopt(parse(from_os_str), value_name = "FILE")]
 	source: PathBuf,
 
-	/// Process ID of the node left of this one.
-	#[structopt(long, value_name = "PID")]
-	left: Option<i32>,
+	/// Socket path shared with the node left of this one.
+	#[structopt(long, parse(from_os_str), value_name = "PATH")]
+	left: Option<PathBuf>,
+
+	/// Socket path shared with the node right of this one.
+	#[structopt(long, parse(from_os_str), value_name = "PATH")]
+	right: Option<PathBuf>,
 
-	/// Process ID of the node right of this one.
-	#[structopt(long, value_name = "PID")]
-	right: Option<i32>,
+	/// Socket path shared with the node above this one.
+	#[structopt(long, parse(from_os_str), value_name = "PATH")]
+	up: Option<PathBuf>,
 
-	/// Process ID of the node above this one.
-	#[structopt(long, value_name = "PID")]
-	up: Option<i32>,
+	/// Socket path shared with the node below this one.
+	#[structopt(long, parse(from_os_str), value_name = "PATH")]
+	down: Option<PathBuf>,
 
-	/// Process ID of the node below this one.
-	#[structopt(long, value_name = "PID")]
-	down: Option<i32>,
+	/// Print this node's acc/bak/last, program counter, and port status
+	/// every cycle.
+	#[structopt(long)]
+	display: bool,
 }
 
 fn main() {
 	let args = Args::from_args();
 
-	let mut node = Node::new(
-		PeerPids {
-			left: args.left,
-			right: args.right,
-			up: args.up,
-			down: args.down,
-		},
-		3, // Next file descriptor after std{in,out,err} is 3.
-	);
-
-	eprintln!("PID of this node: {}", std::process::id());
+	let source = std::fs::read_to_string(&args.source).unwrap();
+	let program = Program::parse(&source);
 
-	let x = std::process::id() as i32 % 100;
-
-	// TODO: Execute the program.
-	// TODO: Show output/state.
+	let mut node = Node::new(PeerAddrs {
+		left: args.left,
+		right: args.right,
+		up: args.up,
+		down: args.down,
+	});
 
+	let mut interpreter = Interpreter::new();
+	let mut cycle = 0;
 	loop {
-		if args.left.is_some() || args.right.is_some() || args.up.is_some() || args.down.is_some() {
-			for &i in &[100 + x, 200 + x, 300 + x] {
-				eprint!("Sending {}...", i);
-				node.write(i, Register::Any);
-				eprintln!("done");
-			}
+		let progressed = interpreter.step(&mut node, &program).is_ready();
+		if args.display {
+			eprintln!("cycle {}: {}", cycle, puzzle::render_node(&node, interpreter.pc()));
+		}
+		if progressed {
+			cycle += 1;
 		} else {
-			dbg!(node.read(Register::Any));
-			std::thread::sleep(std::time::Duration::from_secs(1));
+			node.block_until_ready();
 		}
 	}
 }
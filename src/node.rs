@@ -1,9 +1,11 @@
 use arraymap::ArrayMap;
 use nix::poll::{poll, PollFd, PollFlags};
-use nix::unistd::pipe;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Lines, Write};
-use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::task::Poll;
+use std::time::Duration;
 
 const POLLIN: PollFlags = PollFlags::POLLIN;
 
@@ -15,6 +17,15 @@ pub enum Side {
 	Down,
 }
 
+/// What a blocked side of a node is stuck waiting on.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BlockedOn {
+	/// A value has been sent and this side is waiting for the ACK/NAK reply.
+	Send,
+	/// A `GET` has been sent and this side is waiting for a value.
+	Read,
+}
+
 impl Side {
 	fn index(self) -> usize {
 		self as usize
@@ -30,124 +41,156 @@ impl Side {
 			_ => unreachable!(),
 		}
 	}
-
-	fn opposite(self) -> Self {
-		use Side::*;
-		match self {
-			Left => Right,
-			Right => Left,
-			Up => Down,
-			Down => Up,
-		}
-	}
-}
-
-#[derive(Debug)]
-struct Pipe {
-	read: File,
-	write: File,
-}
-
-impl Pipe {
-	fn new() -> Self {
-		let (read, write) = pipe().unwrap();
-		unsafe {
-			Self {
-				read: File::from_raw_fd(read),
-				write: File::from_raw_fd(write),
-			}
-		}
-	}
 }
 
 #[derive(Debug)]
 struct Peer {
-	output: File,
-	input: Lines<BufReader<File>>,
+	output: UnixStream,
+	input: UnixStream,
 	input_fd: i32,
-	output_read: File, // Just to keep this file descriptor alive.
-	input_write: File, // Just to keep this file descriptor alive.
+	// Bytes already read off `input` but not yet split into a line. A single
+	// `read` can return more than one line's worth of bytes (or less than
+	// one), so this has to be tracked across `poll_line` calls rather than
+	// assuming one read lines up with one message.
+	input_buf: Vec<u8>,
+	// Only set for sides without a neighbor. Keeps the other end of the
+	// socket pair alive so reads simply block forever instead of seeing EOF.
+	_unconnected_end: Option<UnixStream>,
 	sent_get: bool,
 	got_get: bool,
 	cancelled_gets: usize,
+	// Set once a value has been written and we're waiting on the ACK/NAK
+	// reply, so a re-entrant `try_send` doesn't write the value twice.
+	awaiting_reply: bool,
 }
 
-fn open(path: &str, write: bool) -> File {
-	std::fs::OpenOptions::new()
-		.read(!write)
-		.write(write)
-		.open(path)
-		.unwrap()
+/// Connects the link for one side of a node. `Side::Left`/`Side::Up` bind
+/// and accept, `Side::Right`/`Side::Down` connect as a client; the two
+/// nodes on either end of a link must be given the same socket path, one
+/// using it on its `left`/`up` side and the other on its `right`/`down`
+/// side.
+fn connect(side: Side, path: &Path) -> UnixStream {
+	match side {
+		Side::Left | Side::Up => {
+			let _ = std::fs::remove_file(path);
+			let listener = std::os::unix::net::UnixListener::bind(path).unwrap();
+			let (stream, _) = listener.accept().unwrap();
+			stream
+		}
+		Side::Right | Side::Down => loop {
+			match UnixStream::connect(path) {
+				Ok(stream) => break stream,
+				Err(_) => std::thread::sleep(Duration::from_millis(50)),
+			}
+		},
+	}
 }
 
 impl Peer {
-	fn new(side: Side, pid: Option<i32>, fd_offset: i32) -> Self {
-		let fd_offset = |side: Side| side.index() as i32 * 4 + fd_offset;
-		let (output, input) = if let Some(pid) = pid {
-			let offset = fd_offset(side.opposite());
-			(
-				Pipe {
-					read: open(&format!("/proc/{}/fd/{}", pid, offset + 2), false),
-					write: open(&format!("/proc/{}/fd/{}", pid, offset + 3), true),
-				},
-				Pipe {
-					read: open(&format!("/proc/{}/fd/{}", pid, offset), false),
-					write: open(&format!("/proc/{}/fd/{}", pid, offset + 1), true),
-				},
-			)
-		} else {
-			(Pipe::new(), Pipe::new())
+	fn new(side: Side, path: Option<&Path>) -> Self {
+		let (stream, unconnected_end) = match path {
+			Some(path) => (connect(side, path), None),
+			None => {
+				let (ours, theirs) = UnixStream::pair().unwrap();
+				(ours, Some(theirs))
+			}
 		};
-		let offset = fd_offset(side);
-		assert_eq!(output.read.as_raw_fd(), offset);
-		assert_eq!(output.write.as_raw_fd(), offset + 1);
-		assert_eq!(input.read.as_raw_fd(), offset + 2);
-		assert_eq!(input.write.as_raw_fd(), offset + 3);
+		Self::from_stream(stream, unconnected_end)
+	}
+
+	fn from_stream(stream: UnixStream, unconnected_end: Option<UnixStream>) -> Self {
+		let output = stream.try_clone().unwrap();
+		let input_fd = stream.as_raw_fd();
 		Self {
-			output: output.write,
-			input_fd: input.read.as_raw_fd(),
-			input: BufReader::new(input.read).lines(),
-			output_read: output.read,
-			input_write: input.write,
+			output,
+			input: stream,
+			input_fd,
+			input_buf: Vec::new(),
+			_unconnected_end: unconnected_end,
 			sent_get: false,
 			got_get: false,
 			cancelled_gets: 0,
+			awaiting_reply: false,
 		}
 	}
 
-	fn send(&mut self, value: i32) {
-		while !self.try_send(value) {}
+	/// Whether this side is currently stuck mid-handshake, and on what.
+	fn blocked(&self) -> Option<BlockedOn> {
+		if self.awaiting_reply {
+			Some(BlockedOn::Send)
+		} else if self.sent_get {
+			Some(BlockedOn::Read)
+		} else {
+			None
+		}
 	}
 
-	fn try_send(&mut self, value: i32) -> bool {
-		assert!(!self.sent_get);
-		if self.got_get {
-			self.got_get = false;
-		} else {
-			match self.input.next().unwrap().unwrap().as_str() {
-				"GET" => {}
-				x if self.cancelled_gets > 0 && x.parse::<i32>().is_ok() => {
-					self.cancelled_gets -= 1;
-					return false;
-				}
-				_ => panic!("unexpected communication"),
+	/// Reads one line from this peer, without blocking. This is the only
+	/// place that reads from the stream, which is what lets every handshake
+	/// step below be resumed from wherever it left off instead of blocking.
+	///
+	/// `POLLIN` only promises that *some* bytes are available, not that a
+	/// full line is: a line can arrive split across multiple reads, and a
+	/// single read can also pull in more than one line's worth of bytes at
+	/// once. So this keeps reading into `input_buf` for as long as more data
+	/// is available, and only returns `Poll::Pending` once polling shows
+	/// nothing left to read and still no full line has accumulated.
+	fn poll_line(&mut self) -> Poll<String> {
+		loop {
+			if let Some(i) = self.input_buf.iter().position(|&b| b == b'\n') {
+				let rest = self.input_buf.split_off(i + 1);
+				let mut line = std::mem::replace(&mut self.input_buf, rest);
+				line.pop(); // drop the newline itself
+				return Poll::Ready(String::from_utf8(line).unwrap());
+			}
+
+			let mut fds = [PollFd::new(self.input_fd, POLLIN)];
+			poll(&mut fds, 0).unwrap();
+			if !fds[0].revents().unwrap().contains(POLLIN) {
+				return Poll::Pending;
+			}
+
+			let mut chunk = [0u8; 4096];
+			match self.input.read(&mut chunk) {
+				Ok(0) => panic!("peer closed connection"),
+				Ok(n) => self.input_buf.extend_from_slice(&chunk[..n]),
+				Err(e) => panic!("read error: {}", e),
 			}
-		}
-		self.output
-			.write_all(format!("{}\n", value).as_bytes())
-			.unwrap();
-		match self.input.next().unwrap().unwrap().as_str() {
-			"ACK" => true,
-			"NAK" => false,
-			x => panic!("unexpected reply {:?}", x),
 		}
 	}
 
-	fn read(&mut self) -> i32 {
-		loop {
-			self.request_read();
-			if let Some(value) = self.finish_read() {
-				return value;
+	fn try_send(&mut self, value: i32) -> Poll<bool> {
+		assert!(!self.sent_get);
+		if !self.awaiting_reply {
+			if self.got_get {
+				self.got_get = false;
+			} else {
+				match self.poll_line() {
+					Poll::Pending => return Poll::Pending,
+					Poll::Ready(line) => match line.as_str() {
+						"GET" => {}
+						x if self.cancelled_gets > 0 && x.parse::<i32>().is_ok() => {
+							self.cancelled_gets -= 1;
+							return Poll::Ready(false);
+						}
+						_ => panic!("unexpected communication"),
+					},
+				}
+			}
+			self.output
+				.write_all(format!("{}\n", value).as_bytes())
+				.unwrap();
+			self.awaiting_reply = true;
+		}
+		match self.poll_line() {
+			Poll::Pending => Poll::Pending,
+			Poll::Ready(line) => {
+				self.awaiting_reply = false;
+				match line.as_str() {
+					"ACK" => Poll::Ready(true),
+					"NAK" => Poll::Ready(false),
+					x => panic!("unexpected reply {:?}", x),
+				}
 			}
 		}
 	}
@@ -159,9 +202,13 @@ impl Peer {
 		}
 	}
 
-	fn finish_read(&mut self) -> Option<i32> {
+	fn finish_read(&mut self) -> Poll<Option<i32>> {
 		assert!(self.sent_get);
-		match self.input.next().unwrap().unwrap().as_str() {
+		let line = match self.poll_line() {
+			Poll::Pending => return Poll::Pending,
+			Poll::Ready(line) => line,
+		};
+		Poll::Ready(match line.as_str() {
 			"GET" if !self.got_get => {
 				self.got_get = true;
 				None
@@ -183,7 +230,7 @@ impl Peer {
 				}
 				_ => panic!("unexpected reply"),
 			},
-		}
+		})
 	}
 
 	fn cancel_read(&mut self) {
@@ -203,12 +250,14 @@ pub struct Node {
 	last: Option<Side>,
 }
 
-#[derive(Debug)]
-pub struct PeerPids {
-	pub left: Option<i32>,
-	pub right: Option<i32>,
-	pub up: Option<i32>,
-	pub down: Option<i32>,
+/// Per-side socket paths for a node's neighbors. A side left as `None` has
+/// no neighbor: reads and writes on it simply block forever.
+#[derive(Debug, Default)]
+pub struct PeerAddrs {
+	pub left: Option<PathBuf>,
+	pub right: Option<PathBuf>,
+	pub up: Option<PathBuf>,
+	pub down: Option<PathBuf>,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -222,13 +271,13 @@ pub enum Register {
 }
 
 impl Node {
-	pub fn new(peers: PeerPids, fd_offset: i32) -> Self {
+	pub fn new(peers: PeerAddrs) -> Self {
 		Self {
 			peers: [
-				Peer::new(Side::Left, peers.left, fd_offset),
-				Peer::new(Side::Right, peers.right, fd_offset),
-				Peer::new(Side::Up, peers.up, fd_offset),
-				Peer::new(Side::Down, peers.down, fd_offset),
+				Peer::new(Side::Left, peers.left.as_deref()),
+				Peer::new(Side::Right, peers.right.as_deref()),
+				Peer::new(Side::Up, peers.up.as_deref()),
+				Peer::new(Side::Down, peers.down.as_deref()),
 			],
 			acc: 0,
 			bak: 0,
@@ -236,72 +285,195 @@ impl Node {
 		}
 	}
 
+	/// Creates a node with all four sides unconnected: reads and writes on
+	/// any of them block forever until wired up with [`Node::connect`].
+	pub fn new_unconnected() -> Self {
+		Self::new(PeerAddrs::default())
+	}
+
+	/// Wires this node's `side` directly to `other`'s `other_side`, without
+	/// going through the filesystem. Used to host several nodes in the same
+	/// process (see the `runtime` module).
+	pub fn connect(&mut self, side: Side, other: &mut Node, other_side: Side) {
+		let (ours, theirs) = UnixStream::pair().unwrap();
+		self.peers[side.index()] = Peer::from_stream(ours, None);
+		other.peers[other_side.index()] = Peer::from_stream(theirs, None);
+	}
+
+	/// Whether `side` is currently stuck mid-handshake, and on what.
+	pub(crate) fn side_blocked(&self, side: Side) -> Option<BlockedOn> {
+		self.peers[side.index()].blocked()
+	}
+
+	pub(crate) fn acc(&self) -> i32 {
+		self.acc
+	}
+
+	pub(crate) fn bak(&self) -> i32 {
+		self.bak
+	}
+
+	pub(crate) fn last(&self) -> Option<Side> {
+		self.last
+	}
+
+	/// Blocks until `value` has been written to `target`.
 	pub fn write(&mut self, value: i32, target: Register) {
-		match (target, self.last) {
-			(Register::Acc, _) => self.acc = value,
-			(Register::Bak, _) => self.bak = value,
-			(Register::Nil, _) | (Register::Last, None) => (),
-			(Register::Side(s), _) | (Register::Last, Some(s)) => self.peers[s.index()].send(value),
-			(Register::Any, _) => self.write_any(value),
+		while self.write_poll(value, target).is_pending() {
+			self.block_until_ready();
 		}
 	}
 
-	fn write_any(&mut self, value: i32) {
-		for i in 0..4 {
-			if self.peers[i].got_get {
-				if self.peers[i].try_send(value) {
-					return;
+	/// Attempts to write `value` to `target`, without blocking. Returns
+	/// `Poll::Pending` if `target` is a port that isn't ready yet; calling
+	/// this again later resumes the handshake rather than restarting it.
+	pub(crate) fn write_poll(&mut self, value: i32, target: Register) -> Poll<()> {
+		match (target, self.last) {
+			(Register::Acc, _) => {
+				self.acc = value;
+				Poll::Ready(())
+			}
+			(Register::Bak, _) => {
+				self.bak = value;
+				Poll::Ready(())
+			}
+			(Register::Nil, _) | (Register::Last, None) => Poll::Ready(()),
+			(Register::Side(s), _) | (Register::Last, Some(s)) => {
+				match self.peers[s.index()].try_send(value) {
+					Poll::Ready(true) => Poll::Ready(()),
+					Poll::Ready(false) | Poll::Pending => Poll::Pending,
 				}
 			}
+			(Register::Any, _) => self.write_any_poll(value),
 		}
-		let mut fds = self.peers.map(|p| PollFd::new(p.input_fd, POLLIN));
-		loop {
-			poll(&mut fds, -1).unwrap();
-			for i in 0..4 {
-				if fds[i].revents().unwrap().contains(POLLIN) {
-					if self.peers[i].try_send(value) {
-						self.last = Some(Side::from_index(i));
-						return;
+	}
+
+	fn write_any_poll(&mut self, value: i32) -> Poll<()> {
+		// If an earlier call already committed to a peer (it saw that
+		// peer's GET and wrote `value` to it, and is now just waiting on
+		// the ACK/NAK reply), keep polling only that peer: trying another
+		// one too could send `value` to two peers at once.
+		if let Some(i) = (0..4).find(|&i| self.peers[i].awaiting_reply) {
+			return match self.peers[i].try_send(value) {
+				Poll::Ready(true) => {
+					self.last = Some(Side::from_index(i));
+					Poll::Ready(())
+				}
+				Poll::Ready(false) | Poll::Pending => Poll::Pending,
+			};
+		}
+
+		for i in 0..4 {
+			match self.peers[i].try_send(value) {
+				Poll::Ready(true) => {
+					self.last = Some(Side::from_index(i));
+					return Poll::Ready(());
+				}
+				Poll::Ready(false) => {}
+				Poll::Pending => {
+					if self.peers[i].awaiting_reply {
+						// `value` has just been written to this peer, so
+						// stop here instead of trying the rest.
+						return Poll::Pending;
 					}
 				}
 			}
 		}
+		Poll::Pending
 	}
 
+	/// Blocks until a value can be read from `target`.
 	pub fn read(&mut self, target: Register) -> i32 {
-		match (target, self.last) {
-			(Register::Acc, _) => self.acc,
-			(Register::Bak, _) => self.bak,
-			(Register::Nil, _) | (Register::Last, None) => 0,
-			(Register::Side(s), _) | (Register::Last, Some(s)) => self.peers[s.index()].read(),
-			(Register::Any, _) => self.read_any(),
+		loop {
+			if let Poll::Ready(value) = self.read_poll(target) {
+				return value;
+			}
+			self.block_until_ready();
 		}
 	}
 
-	fn read_any(&mut self) -> i32 {
-		let mut fds = self.peers.map(|p| PollFd::new(p.input_fd, POLLIN));
-		let mut value = None;
-		loop {
-			for p in &mut self.peers {
-				p.request_read();
-			}
-			poll(&mut fds, -1).unwrap();
-			for i in 0..4 {
-				if fds[i].revents().unwrap().contains(POLLIN) {
-					if let Some(x) = self.peers[i].finish_read() {
-						value = Some(x);
-						self.last = Some(Side::from_index(i));
-						break;
-					}
+	/// Attempts to read a value from `target`, without blocking. Returns
+	/// `Poll::Pending` if `target` is a port with no value ready yet;
+	/// calling this again later resumes the handshake rather than
+	/// restarting it.
+	pub(crate) fn read_poll(&mut self, target: Register) -> Poll<i32> {
+		match (target, self.last) {
+			(Register::Acc, _) => Poll::Ready(self.acc),
+			(Register::Bak, _) => Poll::Ready(self.bak),
+			(Register::Nil, _) | (Register::Last, None) => Poll::Ready(0),
+			(Register::Side(s), _) | (Register::Last, Some(s)) => {
+				let peer = &mut self.peers[s.index()];
+				peer.request_read();
+				match peer.finish_read() {
+					Poll::Ready(Some(value)) => Poll::Ready(value),
+					Poll::Ready(None) | Poll::Pending => Poll::Pending,
 				}
 			}
-			if let Some(value) = value {
-				// Got a value. Cancel all the pending requests.
-				for i in 0..4 {
-					self.peers[i].cancel_read();
+			(Register::Any, _) => self.read_any_poll(),
+		}
+	}
+
+	fn read_any_poll(&mut self) -> Poll<i32> {
+		for p in &mut self.peers {
+			p.request_read();
+		}
+		for i in 0..4 {
+			if let Poll::Ready(Some(value)) = self.peers[i].finish_read() {
+				self.last = Some(Side::from_index(i));
+				for p in &mut self.peers {
+					p.cancel_read();
 				}
-				return value;
+				return Poll::Ready(value);
 			}
 		}
+		Poll::Pending
+	}
+
+	/// The raw file descriptors to watch for readiness on this node's four
+	/// sides, for a poll-based executor to wait on.
+	pub(crate) fn input_fds(&self) -> [i32; 4] {
+		// `ArrayMap::map` is called out explicitly: the inherent, by-value
+		// `[T; N]::map` would otherwise win overload resolution and move
+		// `self.peers` out of `self`.
+		ArrayMap::map(&self.peers, |p| p.input_fd)
+	}
+
+	/// Blocks the calling thread until any of this node's peers might have
+	/// made progress.
+	pub fn block_until_ready(&self) {
+		let mut fds: [PollFd; 4] = ArrayMap::map(&self.peers, |p| PollFd::new(p.input_fd, POLLIN));
+		poll(&mut fds, -1).unwrap();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// If two peers both have a `GET` outstanding when a `MOV ..., ANY` tries
+	/// to send, `write_any_poll` must commit to whichever one it finds ready
+	/// first instead of also writing the same value to the other while the
+	/// first is still waiting on its ACK.
+	#[test]
+	fn write_any_commits_to_first_ready_peer_only() {
+		let mut node = Node::new_unconnected();
+		let mut left = Node::new_unconnected();
+		let mut right = Node::new_unconnected();
+		node.connect(Side::Left, &mut left, Side::Right);
+		node.connect(Side::Right, &mut right, Side::Left);
+
+		// Both neighbors ask for a value up front, so the single scan below
+		// sees every side ready at once.
+		assert!(left.read_poll(Register::Side(Side::Right)).is_pending());
+		assert!(right.read_poll(Register::Side(Side::Left)).is_pending());
+
+		// `Left` is tried first and accepts the value, leaving it awaiting
+		// an ACK; `write_any_poll` must commit to it rather than also
+		// writing the same value to `Right`.
+		assert!(node.write_any_poll(99).is_pending());
+		assert!(
+			right.read_poll(Register::Side(Side::Left)).is_pending(),
+			"value must not be sent to a second peer while the first is still awaiting its ACK"
+		);
 	}
 }
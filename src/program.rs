@@ -0,0 +1,377 @@
+//! Parses TIS-100 assembly source into a [`Program`] and executes it one
+//! instruction at a time against a [`Node`].
+
+use crate::node::{Node, Register, Side};
+use std::collections::HashMap;
+use std::task::Poll;
+
+/// The source of a value: either an integer literal or a register.
+#[derive(Clone, Copy, Debug)]
+pub enum Src {
+	Int(i32),
+	Reg(Register),
+}
+
+/// A single decoded instruction. Jump targets are resolved to instruction
+/// indices at parse time, so stepping never has to look labels up again.
+#[derive(Clone, Copy, Debug)]
+pub enum Instruction {
+	Nop,
+	Mov(Src, Register),
+	Swp,
+	Sav,
+	Add(Src),
+	Sub(Src),
+	Neg,
+	Jmp(usize),
+	Jez(usize),
+	Jnz(usize),
+	Jgz(usize),
+	Jlz(usize),
+	Jro(Src),
+}
+
+/// A parsed, ready-to-run program for a single node.
+#[derive(Debug)]
+pub struct Program {
+	instructions: Vec<Instruction>,
+}
+
+/// An undecoded instruction, as produced by the first parsing pass, before
+/// jump targets (which may refer to labels defined later in the source)
+/// have been resolved to instruction indices.
+enum RawInstruction {
+	Nop,
+	Mov(Src, Register),
+	Swp,
+	Sav,
+	Add(Src),
+	Sub(Src),
+	Neg,
+	Jmp(String),
+	Jez(String),
+	Jnz(String),
+	Jgz(String),
+	Jlz(String),
+	Jro(Src),
+}
+
+impl Program {
+	/// Parses TIS-100 assembly. Panics on malformed source, the same way the
+	/// rest of this crate treats its inputs as trusted.
+	pub fn parse(source: &str) -> Self {
+		let mut labels = HashMap::new();
+		let mut lines = Vec::new();
+		for line in source.lines() {
+			let line = match line.find('#') {
+				Some(i) => &line[..i],
+				None => line,
+			};
+			let mut rest = line.trim();
+			while let Some(i) = rest.find(':') {
+				let label = rest[..i].trim();
+				if label.is_empty() {
+					panic!("empty label");
+				}
+				labels.insert(label.to_string(), lines.len());
+				rest = rest[i + 1..].trim();
+			}
+			if !rest.is_empty() {
+				lines.push(rest);
+			}
+		}
+
+		let raw: Vec<RawInstruction> = lines.iter().map(|line| parse_line(line)).collect();
+
+		let resolve = |label: String| -> usize {
+			*labels
+				.get(&label)
+				.unwrap_or_else(|| panic!("undefined label {:?}", label))
+		};
+
+		let instructions = raw
+			.into_iter()
+			.map(|instr| match instr {
+				RawInstruction::Nop => Instruction::Nop,
+				RawInstruction::Mov(src, dst) => Instruction::Mov(src, dst),
+				RawInstruction::Swp => Instruction::Swp,
+				RawInstruction::Sav => Instruction::Sav,
+				RawInstruction::Add(src) => Instruction::Add(src),
+				RawInstruction::Sub(src) => Instruction::Sub(src),
+				RawInstruction::Neg => Instruction::Neg,
+				RawInstruction::Jmp(label) => Instruction::Jmp(resolve(label)),
+				RawInstruction::Jez(label) => Instruction::Jez(resolve(label)),
+				RawInstruction::Jnz(label) => Instruction::Jnz(resolve(label)),
+				RawInstruction::Jgz(label) => Instruction::Jgz(resolve(label)),
+				RawInstruction::Jlz(label) => Instruction::Jlz(resolve(label)),
+				RawInstruction::Jro(src) => Instruction::Jro(src),
+			})
+			.collect();
+
+		Self { instructions }
+	}
+
+	pub fn len(&self) -> usize {
+		self.instructions.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.instructions.is_empty()
+	}
+}
+
+fn parse_line(line: &str) -> RawInstruction {
+	let mut parts = line.splitn(2, char::is_whitespace);
+	let op = parts.next().unwrap();
+	let rest = parts.next().unwrap_or("").trim();
+	let args: Vec<&str> = if rest.is_empty() {
+		Vec::new()
+	} else {
+		rest.split(',').map(|s| s.trim()).collect()
+	};
+
+	fn one<'a>(op: &str, args: &[&'a str]) -> &'a str {
+		match args {
+			[a] => a,
+			_ => panic!("{} expects 1 argument, got {}", op, args.len()),
+		}
+	}
+
+	match op.to_ascii_uppercase().as_str() {
+		"NOP" => RawInstruction::Nop,
+		"SWP" => RawInstruction::Swp,
+		"SAV" => RawInstruction::Sav,
+		"NEG" => RawInstruction::Neg,
+		"MOV" => match args.as_slice() {
+			[src, dst] => RawInstruction::Mov(parse_src(src), parse_register(dst)),
+			_ => panic!("MOV expects 2 arguments, got {}", args.len()),
+		},
+		"ADD" => RawInstruction::Add(parse_src(one("ADD", &args))),
+		"SUB" => RawInstruction::Sub(parse_src(one("SUB", &args))),
+		"JRO" => RawInstruction::Jro(parse_src(one("JRO", &args))),
+		"JMP" => RawInstruction::Jmp(one("JMP", &args).to_string()),
+		"JEZ" => RawInstruction::Jez(one("JEZ", &args).to_string()),
+		"JNZ" => RawInstruction::Jnz(one("JNZ", &args).to_string()),
+		"JGZ" => RawInstruction::Jgz(one("JGZ", &args).to_string()),
+		"JLZ" => RawInstruction::Jlz(one("JLZ", &args).to_string()),
+		other => panic!("unknown instruction {:?}", other),
+	}
+}
+
+fn parse_src(tok: &str) -> Src {
+	match tok.parse::<i32>() {
+		Ok(n) => Src::Int(n),
+		Err(_) => Src::Reg(parse_register(tok)),
+	}
+}
+
+/// Parses a register name. `BAK` is deliberately not accepted here: it is
+/// only reachable through the dedicated `SWP`/`SAV` instructions, never as a
+/// `MOV` source or destination.
+fn parse_register(tok: &str) -> Register {
+	match tok.to_ascii_uppercase().as_str() {
+		"ACC" => Register::Acc,
+		"NIL" => Register::Nil,
+		"LEFT" => Register::Side(Side::Left),
+		"RIGHT" => Register::Side(Side::Right),
+		"UP" => Register::Side(Side::Up),
+		"DOWN" => Register::Side(Side::Down),
+		"ANY" => Register::Any,
+		"LAST" => Register::Last,
+		other => panic!("unknown register {:?}", other),
+	}
+}
+
+/// Clamps a value to the inclusive range every `ACC` value and every value
+/// crossing a port must stay within.
+fn clamp(value: i32) -> i32 {
+	value.clamp(-999, 999)
+}
+
+/// Steps a single node's program counter through its `Program`, driving
+/// reads and writes on the node as it goes.
+#[derive(Debug)]
+pub struct Interpreter {
+	pc: usize,
+	/// A `MOV`'s already-resolved source value, held here across a pending
+	/// destination write so resuming the instruction writes that same value
+	/// instead of reading the source again.
+	pending_mov_value: Option<i32>,
+}
+
+impl Default for Interpreter {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Interpreter {
+	pub fn new() -> Self {
+		Self {
+			pc: 0,
+			pending_mov_value: None,
+		}
+	}
+
+	pub fn pc(&self) -> usize {
+		self.pc
+	}
+
+	fn read_src_poll(node: &mut Node, src: Src) -> Poll<i32> {
+		match src {
+			Src::Int(n) => Poll::Ready(n),
+			Src::Reg(r) => node.read_poll(r),
+		}
+	}
+
+	/// Attempts to execute the instruction at the current program counter,
+	/// without blocking. Returns `Poll::Pending` if it's a `MOV` (or an
+	/// `ADD`, `SUB` or `JRO`) whose source or destination is a port that
+	/// isn't ready yet; the
+	/// program counter only advances once the instruction has fully
+	/// completed, and calling this again resumes the same instruction
+	/// rather than restarting it, since the underlying port handshake is
+	/// itself resumable.
+	pub fn step(&mut self, node: &mut Node, program: &Program) -> Poll<()> {
+		if program.is_empty() {
+			return Poll::Ready(());
+		}
+
+		let mut next_pc = self.pc + 1;
+
+		match program.instructions[self.pc] {
+			Instruction::Nop => (),
+			Instruction::Mov(src, dst) => {
+				// Source is always resolved before the destination, so e.g.
+				// `MOV ANY, ANY` reads from whichever side answers first
+				// (updating `last`) before deciding where to write it. The
+				// resolved value is cached in `pending_mov_value` so that if
+				// the destination write is still pending, resuming this
+				// instruction retries the write with that same value
+				// instead of reading the source a second time.
+				let value = match self.pending_mov_value {
+					Some(value) => value,
+					None => {
+						let value = match Self::read_src_poll(node, src) {
+							Poll::Ready(value) => clamp(value),
+							Poll::Pending => return Poll::Pending,
+						};
+						self.pending_mov_value = Some(value);
+						value
+					}
+				};
+				match node.write_poll(value, dst) {
+					Poll::Ready(()) => self.pending_mov_value = None,
+					Poll::Pending => return Poll::Pending,
+				}
+			}
+			Instruction::Swp => {
+				let acc = node.read(Register::Acc);
+				let bak = node.read(Register::Bak);
+				node.write(bak, Register::Acc);
+				node.write(acc, Register::Bak);
+			}
+			Instruction::Sav => {
+				let acc = node.read(Register::Acc);
+				node.write(acc, Register::Bak);
+			}
+			Instruction::Add(src) => {
+				let value = match Self::read_src_poll(node, src) {
+					Poll::Ready(value) => value,
+					Poll::Pending => return Poll::Pending,
+				};
+				let acc = node.read(Register::Acc);
+				node.write(clamp(acc + value), Register::Acc);
+			}
+			Instruction::Sub(src) => {
+				let value = match Self::read_src_poll(node, src) {
+					Poll::Ready(value) => value,
+					Poll::Pending => return Poll::Pending,
+				};
+				let acc = node.read(Register::Acc);
+				node.write(clamp(acc - value), Register::Acc);
+			}
+			Instruction::Neg => {
+				let acc = node.read(Register::Acc);
+				node.write(clamp(-acc), Register::Acc);
+			}
+			Instruction::Jmp(target) => next_pc = target,
+			Instruction::Jez(target) => {
+				if node.read(Register::Acc) == 0 {
+					next_pc = target;
+				}
+			}
+			Instruction::Jnz(target) => {
+				if node.read(Register::Acc) != 0 {
+					next_pc = target;
+				}
+			}
+			Instruction::Jgz(target) => {
+				if node.read(Register::Acc) > 0 {
+					next_pc = target;
+				}
+			}
+			Instruction::Jlz(target) => {
+				if node.read(Register::Acc) < 0 {
+					next_pc = target;
+				}
+			}
+			Instruction::Jro(src) => {
+				let offset = match Self::read_src_poll(node, src) {
+					Poll::Ready(offset) => offset as i64,
+					Poll::Pending => return Poll::Pending,
+				};
+				let target = (self.pc as i64 + offset).clamp(0, program.len() as i64 - 1);
+				next_pc = target as usize;
+			}
+		}
+
+		self.pc = if next_pc >= program.len() { 0 } else { next_pc };
+		Poll::Ready(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A pass-through `MOV LEFT, RIGHT` must forward every value exactly
+	/// once, even when its write to `RIGHT` has to wait (because the sink
+	/// isn't reading yet): resuming the instruction must retry the write
+	/// with the value already read from `LEFT`, not read `LEFT` again.
+	#[test]
+	fn mov_passthrough_does_not_reread_or_drop_values() {
+		let mut passthrough = Node::new_unconnected();
+		let mut source = Node::new_unconnected();
+		let mut sink = Node::new_unconnected();
+		passthrough.connect(Side::Left, &mut source, Side::Right);
+		passthrough.connect(Side::Right, &mut sink, Side::Left);
+
+		let program = Program::parse("MOV LEFT, RIGHT");
+		let passthrough_worker = std::thread::spawn(move || {
+			let mut interpreter = Interpreter::new();
+			for _ in 0..3 {
+				while interpreter.step(&mut passthrough, &program).is_pending() {
+					passthrough.block_until_ready();
+				}
+			}
+		});
+		// Sends all three values without waiting for them to be consumed,
+		// so the passthrough node's write side is guaranteed to see
+		// `Poll::Pending` after it has already consumed the matching read.
+		let source_worker = std::thread::spawn(move || {
+			for value in [10, 20, 30] {
+				source.write(value, Register::Side(Side::Right));
+			}
+		});
+
+		let mut received = Vec::new();
+		for _ in 0..3 {
+			received.push(sink.read(Register::Side(Side::Left)));
+		}
+
+		source_worker.join().unwrap();
+		passthrough_worker.join().unwrap();
+		assert_eq!(received, [10, 20, 30]);
+	}
+}
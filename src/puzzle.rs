@@ -0,0 +1,378 @@
+//! A puzzle harness around a [`Runtime`]: scripted boundary streams that
+//! feed input into a grid and check its output against what's expected,
+//! plus a live view of every node's state for `--display` mode.
+
+use crate::node::{BlockedOn, Node, Register, Side};
+use crate::runtime::Runtime;
+use nix::poll::{poll, PollFd, PollFlags};
+use std::task::Poll;
+
+const POLLIN: PollFlags = PollFlags::POLLIN;
+
+/// A boundary node that writes a scripted sequence of values out of `side`,
+/// one at a time, then goes idle.
+#[derive(Debug)]
+pub struct Source {
+	node: Node,
+	side: Side,
+	values: Vec<i32>,
+	sent: usize,
+}
+
+impl Source {
+	pub fn new(side: Side, values: Vec<i32>) -> Self {
+		Self {
+			node: Node::new_unconnected(),
+			side,
+			values,
+			sent: 0,
+		}
+	}
+
+	pub(crate) fn node_mut(&mut self) -> &mut Node {
+		&mut self.node
+	}
+
+	pub fn is_done(&self) -> bool {
+		self.sent >= self.values.len()
+	}
+
+	/// Writes the next scripted value if the port is ready. Returns whether
+	/// a value was sent this call.
+	fn step(&mut self) -> bool {
+		if self.is_done() {
+			return false;
+		}
+		match self
+			.node
+			.write_poll(self.values[self.sent], Register::Side(self.side))
+		{
+			Poll::Ready(()) => {
+				self.sent += 1;
+				true
+			}
+			Poll::Pending => false,
+		}
+	}
+}
+
+/// A boundary node that reads values arriving on `side` and compares them,
+/// in order, against an expected sequence.
+#[derive(Debug)]
+pub struct Sink {
+	node: Node,
+	side: Side,
+	expected: Vec<i32>,
+	received: Vec<i32>,
+	/// The cycle at which each entry in `received` arrived, parallel to it.
+	received_at_cycle: Vec<usize>,
+}
+
+impl Sink {
+	pub fn new(side: Side, expected: Vec<i32>) -> Self {
+		Self {
+			node: Node::new_unconnected(),
+			side,
+			expected,
+			received: Vec::new(),
+			received_at_cycle: Vec::new(),
+		}
+	}
+
+	pub(crate) fn node_mut(&mut self) -> &mut Node {
+		&mut self.node
+	}
+
+	pub fn is_done(&self) -> bool {
+		self.received.len() >= self.expected.len()
+	}
+
+	fn step(&mut self, cycle: usize) -> bool {
+		if self.is_done() {
+			return false;
+		}
+		match self.node.read_poll(Register::Side(self.side)) {
+			Poll::Ready(value) => {
+				self.received.push(value);
+				self.received_at_cycle.push(cycle);
+				true
+			}
+			Poll::Pending => false,
+		}
+	}
+
+	/// The cycle at which the received sequence first diverged from what was
+	/// expected, if it has.
+	fn mismatch_cycle(&self) -> Option<usize> {
+		let index = self
+			.received
+			.iter()
+			.zip(&self.expected)
+			.position(|(got, want)| got != want)?;
+		Some(self.received_at_cycle[index])
+	}
+
+	fn passed(&self) -> bool {
+		self.received == self.expected
+	}
+}
+
+/// The outcome of a [`Puzzle::run`].
+#[derive(Debug)]
+pub struct Report {
+	pub passed: bool,
+	pub cycles: usize,
+	pub nodes_used: usize,
+	pub instructions_executed: usize,
+	pub mismatch_cycle: Option<usize>,
+}
+
+/// Runs a grid of nodes (hosted by a [`Runtime`]) against scripted boundary
+/// streams: [`Source`]s feed values in, [`Sink`]s collect values out and
+/// check them against an expected sequence.
+pub struct Puzzle {
+	runtime: Runtime,
+	sources: Vec<Source>,
+	sinks: Vec<Sink>,
+	/// Where each source is wired into the grid, so a grid node stuck
+	/// reading from one that will never send another value can be
+	/// recognized as deadlocked: `Runtime::detect_deadlock` only knows about
+	/// links between hosted nodes, not these boundary streams.
+	source_links: Vec<(usize, Side)>,
+	/// Where each sink is wired into the grid, for the same reason: a grid
+	/// node stuck writing to a sink that's already received everything it
+	/// expects is just as deadlocked.
+	sink_links: Vec<(usize, Side)>,
+	cycle: usize,
+}
+
+impl Puzzle {
+	pub fn new(runtime: Runtime) -> Self {
+		Self {
+			runtime,
+			sources: Vec::new(),
+			sinks: Vec::new(),
+			source_links: Vec::new(),
+			sink_links: Vec::new(),
+			cycle: 0,
+		}
+	}
+
+	/// Adds a source stream, wiring its `side` directly to node `id`'s
+	/// `node_side`.
+	pub fn add_source(&mut self, values: Vec<i32>, side: Side, id: usize, node_side: Side) {
+		let mut source = Source::new(side, values);
+		self.runtime
+			.node_mut(id)
+			.connect(node_side, source.node_mut(), side);
+		self.sources.push(source);
+		self.source_links.push((id, node_side));
+	}
+
+	/// Adds a sink stream, wiring its `side` directly to node `id`'s
+	/// `node_side`.
+	pub fn add_sink(&mut self, expected: Vec<i32>, side: Side, id: usize, node_side: Side) {
+		let mut sink = Sink::new(side, expected);
+		self.runtime
+			.node_mut(id)
+			.connect(node_side, sink.node_mut(), side);
+		self.sinks.push(sink);
+		self.sink_links.push((id, node_side));
+	}
+
+	/// Looks for a grid node stuck on a boundary stream that can never
+	/// unblock it: waiting to read from a source that has nothing left to
+	/// send, or waiting for a sink (that's already received everything it
+	/// expects) to acknowledge a write. Returns a diagnostic naming every
+	/// such node/side, if any is found.
+	fn detect_boundary_deadlock(&self) -> Option<String> {
+		let mut report = String::new();
+		for (&(id, side), source) in self.source_links.iter().zip(&self.sources) {
+			if source.is_done() && self.runtime.node(id).side_blocked(side) == Some(BlockedOn::Read)
+			{
+				report.push_str(&format!(
+					"  node {} side {:?}: waiting to read from an exhausted source\n",
+					id, side
+				));
+			}
+		}
+		for (&(id, side), sink) in self.sink_links.iter().zip(&self.sinks) {
+			if sink.is_done() && self.runtime.node(id).side_blocked(side) == Some(BlockedOn::Send) {
+				report.push_str(&format!(
+					"  node {} side {:?}: waiting for a reply from a sink that's already done\n",
+					id, side
+				));
+			}
+		}
+		if report.is_empty() {
+			None
+		} else {
+			Some(report)
+		}
+	}
+
+	fn all_sinks_done(&self) -> bool {
+		self.sinks.iter().all(Sink::is_done)
+	}
+
+	/// Runs the puzzle until every sink has received as many values as it
+	/// expects, or until a deadlock is detected (which is then printed to
+	/// stderr). `display` is called once per cycle, e.g. to render the live
+	/// state of every node.
+	pub fn run(&mut self, mut display: impl FnMut(&Puzzle)) -> Report {
+		loop {
+			display(self);
+			if self.all_sinks_done() {
+				break;
+			}
+
+			let mut progressed = self.runtime.step_once();
+			for source in &mut self.sources {
+				progressed |= source.step();
+			}
+			for sink in &mut self.sinks {
+				progressed |= sink.step(self.cycle);
+			}
+
+			if !progressed {
+				let deadlock = self
+					.runtime
+					.detect_deadlock()
+					.or_else(|| self.detect_boundary_deadlock());
+				if let Some(report) = deadlock {
+					eprintln!("deadlock detected:\n{}", report);
+					break;
+				}
+				self.block_until_ready();
+			}
+
+			self.cycle += 1;
+		}
+		self.report()
+	}
+
+	fn block_until_ready(&self) {
+		let mut fds: Vec<PollFd> = self
+			.runtime
+			.input_fds()
+			.chain(self.sources.iter().flat_map(|s| s.node.input_fds()))
+			.chain(self.sinks.iter().flat_map(|s| s.node.input_fds()))
+			.map(|fd| PollFd::new(fd, POLLIN))
+			.collect();
+		poll(&mut fds, -1).unwrap();
+	}
+
+	fn report(&self) -> Report {
+		let nodes_used = (0..self.runtime.len())
+			.filter(|&id| self.runtime.instructions_executed(id) > 0)
+			.count();
+		let instructions_executed = (0..self.runtime.len())
+			.map(|id| self.runtime.instructions_executed(id))
+			.sum();
+		let mismatch_cycle = self.sinks.iter().filter_map(Sink::mismatch_cycle).min();
+		Report {
+			passed: mismatch_cycle.is_none() && self.sinks.iter().all(Sink::passed),
+			cycles: self.cycle,
+			nodes_used,
+			instructions_executed,
+			mismatch_cycle,
+		}
+	}
+}
+
+/// Renders a compact live-state line for one node: its `acc`/`bak`/`last`,
+/// program counter, and every side's idle/reading/writing status.
+pub fn render_node(node: &Node, pc: usize) -> String {
+	format!(
+		"acc={} bak={} last={:?} pc={} [{}]",
+		node.acc(),
+		node.bak(),
+		node.last(),
+		pc,
+		[Side::Left, Side::Right, Side::Up, Side::Down]
+			.iter()
+			.map(|&side| side_status(node, side))
+			.collect::<Vec<_>>()
+			.join(" "),
+	)
+}
+
+fn side_status(node: &Node, side: Side) -> String {
+	let status = match node.side_blocked(side) {
+		Some(BlockedOn::Send) => "writing",
+		Some(BlockedOn::Read) => "reading",
+		None => "idle",
+	};
+	format!("{:?}:{}", side, status)
+}
+
+/// Renders a live view of every node hosted by `runtime`, for `--display`
+/// mode.
+pub fn render(runtime: &Runtime, cycle: usize) -> String {
+	let mut out = format!("cycle {}\n", cycle);
+	for id in 0..runtime.len() {
+		out.push_str(&format!(
+			"  node {}: {}\n",
+			id,
+			render_node(runtime.node(id), runtime.program_counter(id))
+		));
+	}
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// The mismatching value here is the second one the sink ever reads, so a
+	/// `mismatch_cycle` that (buggily) returned a received-index would report
+	/// `1` regardless of how many idle cycles actually passed before it
+	/// arrived. It must report the cycle the caller passed to `step` instead.
+	#[test]
+	fn mismatch_cycle_records_the_cycle_not_the_received_index() {
+		let mut sink = Sink::new(Side::Left, vec![1, 2, 3]);
+		let mut feeder = Node::new_unconnected();
+		feeder.connect(Side::Right, sink.node_mut(), Side::Left);
+
+		let feeder_worker = std::thread::spawn(move || {
+			for value in [1, 99] {
+				feeder.write(value, Register::Side(Side::Right));
+			}
+		});
+
+		let mut cycle = 0;
+		let mut mismatch_arrived_at = None;
+		while sink.received.len() < 2 {
+			if sink.step(cycle) && sink.received.len() == 2 {
+				mismatch_arrived_at = Some(cycle);
+			}
+			cycle += 1;
+			std::thread::yield_now();
+		}
+		feeder_worker.join().unwrap();
+
+		let mismatch_arrived_at = mismatch_arrived_at.unwrap();
+		assert_ne!(mismatch_arrived_at, 1);
+		assert_eq!(sink.mismatch_cycle(), Some(mismatch_arrived_at));
+	}
+
+	/// `Runtime::detect_deadlock` only knows about links between hosted
+	/// nodes, so a grid stuck reading from an exhausted source isn't a
+	/// runtime-internal cycle at all. Without `detect_boundary_deadlock`,
+	/// `run` would spin forever once the source runs dry instead of
+	/// reporting a failed (incomplete) puzzle.
+	#[test]
+	fn run_terminates_when_the_grid_needs_more_than_a_source_can_provide() {
+		use crate::program::Program;
+
+		let mut runtime = Runtime::new();
+		let node = runtime.spawn(Node::new_unconnected(), Program::parse("MOV LEFT, RIGHT"));
+		let mut puzzle = Puzzle::new(runtime);
+		puzzle.add_source(vec![5], Side::Right, node, Side::Left);
+		puzzle.add_sink(vec![5, 6], Side::Left, node, Side::Right);
+
+		let report = puzzle.run(|_| {});
+
+		assert!(!report.passed);
+	}
+}
@@ -0,0 +1,307 @@
+//! An in-process runtime that hosts many [`Node`]s at once, stepping each
+//! one's program on a small readiness-driven scheduler instead of requiring
+//! one OS process per node, and detecting unbreakable deadlocks between
+//! them instead of hanging forever.
+
+use crate::node::{BlockedOn, Node, Side};
+use crate::program::{Interpreter, Program};
+use nix::poll::{poll, PollFd, PollFlags};
+
+const POLLIN: PollFlags = PollFlags::POLLIN;
+
+struct Task {
+	node: Node,
+	program: Program,
+	interpreter: Interpreter,
+	instructions_executed: usize,
+}
+
+/// A link between two spawned nodes, wired up through [`Runtime::connect`].
+/// Kept around so the deadlock detector knows which sides to compare.
+struct Link {
+	a: (usize, Side),
+	b: (usize, Side),
+}
+
+/// Hosts a grid (or any set) of nodes in a single process.
+#[derive(Default)]
+pub struct Runtime {
+	tasks: Vec<Task>,
+	links: Vec<Link>,
+}
+
+impl Runtime {
+	pub fn new() -> Self {
+		Self {
+			tasks: Vec::new(),
+			links: Vec::new(),
+		}
+	}
+
+	/// Adds a node and the program it should run to the runtime. Returns an
+	/// id to later [`Runtime::connect`] it to other spawned nodes.
+	pub fn spawn(&mut self, node: Node, program: Program) -> usize {
+		self.tasks.push(Task {
+			node,
+			program,
+			interpreter: Interpreter::new(),
+			instructions_executed: 0,
+		});
+		self.tasks.len() - 1
+	}
+
+	/// The number of tasks hosted by this runtime.
+	pub fn len(&self) -> usize {
+		self.tasks.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.tasks.is_empty()
+	}
+
+	pub(crate) fn node(&self, id: usize) -> &Node {
+		&self.tasks[id].node
+	}
+
+	pub(crate) fn node_mut(&mut self, id: usize) -> &mut Node {
+		&mut self.tasks[id].node
+	}
+
+	pub(crate) fn program_counter(&self, id: usize) -> usize {
+		self.tasks[id].interpreter.pc()
+	}
+
+	pub(crate) fn instructions_executed(&self, id: usize) -> usize {
+		self.tasks[id].instructions_executed
+	}
+
+	/// The raw file descriptors to watch for readiness across every hosted
+	/// node, for a poll-based executor to wait on.
+	pub(crate) fn input_fds(&self) -> impl Iterator<Item = i32> + '_ {
+		self.tasks.iter().flat_map(|task| task.node.input_fds())
+	}
+
+	/// Wires node `a`'s `side_a` directly to node `b`'s `side_b`, entirely
+	/// in-process. The link is recorded so the deadlock detector can
+	/// recognize cycles through it.
+	pub fn connect(&mut self, a: usize, side_a: Side, b: usize, side_b: Side) {
+		assert_ne!(a, b, "a node cannot be linked to itself");
+		let (lo, hi) = if a < b { (a, b) } else { (b, a) };
+		let (left, right) = self.tasks.split_at_mut(hi);
+		let (lo_node, hi_node) = (&mut left[lo].node, &mut right[0].node);
+		if a < b {
+			lo_node.connect(side_a, hi_node, side_b);
+		} else {
+			hi_node.connect(side_a, lo_node, side_b);
+		}
+		self.links.push(Link {
+			a: (a, side_a),
+			b: (b, side_b),
+		});
+	}
+
+	/// Runs every spawned node forever. Each pass steps every node's
+	/// program once; a node whose next instruction is waiting on a port
+	/// that isn't ready yet simply makes no progress this pass. Only once a
+	/// whole pass makes no progress anywhere does this check for deadlock
+	/// and otherwise block in the kernel, waking up again as soon as any
+	/// node's peer becomes ready.
+	pub fn run(&mut self) -> ! {
+		loop {
+			if !self.step_once() {
+				if let Some(report) = self.detect_deadlock() {
+					eprintln!("deadlock detected:\n{}", report);
+					std::process::exit(1);
+				}
+				let mut fds: Vec<PollFd> =
+					self.input_fds().map(|fd| PollFd::new(fd, POLLIN)).collect();
+				poll(&mut fds, -1).unwrap();
+			}
+		}
+	}
+
+	/// Steps every hosted node's program once, without blocking. Returns
+	/// whether any node made progress this pass. A node with an empty
+	/// program is inert: `step` trivially returns ready for it every pass,
+	/// but that's not a real instruction, so it doesn't count as progress
+	/// and isn't added to `instructions_executed`.
+	pub(crate) fn step_once(&mut self) -> bool {
+		let mut progressed = false;
+		for task in &mut self.tasks {
+			if task.program.is_empty() {
+				continue;
+			}
+			if task.interpreter.step(&mut task.node, &task.program).is_ready() {
+				task.instructions_executed += 1;
+				progressed = true;
+			}
+		}
+		progressed
+	}
+
+	/// Every side a task is currently stuck on, and why. A task is usually
+	/// blocked on at most one side, but `MOV ANY, ...`/`ADD ANY` etc. send a
+	/// `GET` to every side at once (see `Node::read_any_poll`) and are
+	/// satisfied by whichever replies first, so such a task can be blocked
+	/// on several sides simultaneously.
+	fn blocked_sides(&self, task: usize) -> Vec<(Side, BlockedOn)> {
+		[Side::Left, Side::Right, Side::Up, Side::Down]
+			.iter()
+			.copied()
+			.filter_map(|side| {
+				let reason = self.tasks[task].node.side_blocked(side)?;
+				Some((side, reason))
+			})
+			.collect()
+	}
+
+	/// For every task, the other tasks it's currently waiting on: one for
+	/// each blocked side that's wired to another hosted task via a
+	/// registered link. A task blocked on several sides at once (an
+	/// `ANY`-reader) only needs *one* of them to resolve, so it's only
+	/// truly stuck if every one of them is.
+	fn waits_for_graph(&self) -> Vec<Vec<usize>> {
+		let blocked: Vec<Vec<(Side, BlockedOn)>> =
+			(0..self.tasks.len()).map(|i| self.blocked_sides(i)).collect();
+
+		let mut waits_for = vec![Vec::new(); self.tasks.len()];
+		for link in &self.links {
+			if blocked[link.a.0].iter().any(|&(side, _)| side == link.a.1) {
+				waits_for[link.a.0].push(link.b.0);
+			}
+			if blocked[link.b.0].iter().any(|&(side, _)| side == link.b.1) {
+				waits_for[link.b.0].push(link.a.0);
+			}
+		}
+		waits_for
+	}
+
+	/// Looks for an unbreakable deadlock in the "waits for" graph: a task
+	/// that has at least one wait recorded (so it made no progress this
+	/// pass) is genuinely stuck only if *every* task it's waiting on is
+	/// stuck too -- the same way a simple `MOV`'s single wait must be on a
+	/// stuck peer, but generalized so an `ANY`-reader's several waits must
+	/// *all* be on stuck peers before it counts (any one of them resolving
+	/// would unstick it). Returns a diagnostic naming every stuck node/side,
+	/// if any is found.
+	pub(crate) fn detect_deadlock(&self) -> Option<String> {
+		// A link with one end waiting for the ACK of a value it has already
+		// written to the other end, which is waiting to read it, completes
+		// on the reader's next step: nothing is deadlocked while one exists.
+		let in_flight = self.links.iter().any(|link| {
+			let a = self.tasks[link.a.0].node.side_blocked(link.a.1);
+			let b = self.tasks[link.b.0].node.side_blocked(link.b.1);
+			matches!(
+				(a, b),
+				(Some(BlockedOn::Send), Some(BlockedOn::Read))
+					| (Some(BlockedOn::Read), Some(BlockedOn::Send))
+			)
+		});
+		if in_flight {
+			return None;
+		}
+
+		let stuck = find_stuck(&self.waits_for_graph());
+		if stuck.is_empty() {
+			return None;
+		}
+
+		let mut report = String::new();
+		for task in stuck {
+			for (side, reason) in self.blocked_sides(task) {
+				let reason = match reason {
+					BlockedOn::Read => "waiting to read",
+					BlockedOn::Send => "waiting for a reply",
+				};
+				report.push_str(&format!("  node {} side {:?}: {}\n", task, side, reason));
+			}
+		}
+		Some(report)
+	}
+}
+
+/// Given each node's out-edges (the other nodes it's waiting on, any one of
+/// which resolving would unstick it), finds every node that can never be
+/// unstuck: those with at least one wait, all of whose waits lead to other
+/// such nodes. A node with no waits at all is never reported -- e.g. one
+/// blocked on an unconnected port, which is outside the scope of this check
+/// (it only reasons about registered links).
+fn find_stuck(waits_for: &[Vec<usize>]) -> Vec<usize> {
+	let mut stuck: Vec<bool> = waits_for.iter().map(|edges| !edges.is_empty()).collect();
+	loop {
+		let mut changed = false;
+		for (task, edges) in waits_for.iter().enumerate() {
+			if stuck[task] && edges.iter().any(|&other| !stuck[other]) {
+				stuck[task] = false;
+				changed = true;
+			}
+		}
+		if !changed {
+			break;
+		}
+	}
+	(0..waits_for.len()).filter(|&task| stuck[task]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::program::Program;
+
+	/// Three nodes wired into a ring, each forwarding `LEFT` to `RIGHT` with
+	/// no outside source or sink, deadlock on the very first pass: every node
+	/// sends a `GET` on its `LEFT` side and blocks there, so no pairwise link
+	/// ever has both ends blocked the same way (a check that only compared
+	/// the two ends of one link at a time would miss it). Only following the chain of
+	/// "who's this node waiting on" all the way around the ring reveals it.
+	#[test]
+	fn detects_deadlock_cycle_spanning_three_nodes() {
+		let mut runtime = Runtime::new();
+		let a = runtime.spawn(Node::new_unconnected(), Program::parse("MOV LEFT, RIGHT"));
+		let b = runtime.spawn(Node::new_unconnected(), Program::parse("MOV LEFT, RIGHT"));
+		let c = runtime.spawn(Node::new_unconnected(), Program::parse("MOV LEFT, RIGHT"));
+		runtime.connect(a, Side::Right, b, Side::Left);
+		runtime.connect(b, Side::Right, c, Side::Left);
+		runtime.connect(a, Side::Left, c, Side::Right);
+
+		assert!(!runtime.step_once(), "no node has anything to forward yet");
+		assert!(
+			runtime.detect_deadlock().is_some(),
+			"a 3-node cycle of mutual waits must be reported as a deadlock"
+		);
+	}
+
+	/// `MOV ANY, ...` sends a `GET` to every side, including unconnected
+	/// (dead-end) ones, so a naive check that only looks at a node's first
+	/// blocked side can find a dead end instead of the side that's actually
+	/// linked to another node and genuinely deadlocked. Here `a`'s `Left`
+	/// side is an unconnected dead end that sorts before its real link to
+	/// `b` on `Right`, so this only passes if every blocked side is
+	/// considered, not just the first.
+	#[test]
+	fn detects_deadlock_through_an_any_readers_non_first_side() {
+		let mut runtime = Runtime::new();
+		let a = runtime.spawn(Node::new_unconnected(), Program::parse("MOV ANY, ACC"));
+		let b = runtime.spawn(Node::new_unconnected(), Program::parse("MOV ANY, ACC"));
+		runtime.connect(a, Side::Right, b, Side::Left);
+
+		assert!(!runtime.step_once(), "neither side has anything to offer yet");
+		assert!(
+			runtime.detect_deadlock().is_some(),
+			"an ANY-reader deadlocked through a side other than its first must still be reported"
+		);
+	}
+
+	/// An empty program never has an instruction to execute, so it must not
+	/// count as progress (which would also mask a real deadlock among the
+	/// other nodes, since `step_once` would keep reporting `true`).
+	#[test]
+	fn empty_program_is_inert() {
+		let mut runtime = Runtime::new();
+		let id = runtime.spawn(Node::new_unconnected(), Program::parse(""));
+
+		assert!(!runtime.step_once());
+		assert!(!runtime.step_once());
+		assert_eq!(runtime.instructions_executed(id), 0);
+	}
+}